@@ -1,9 +1,11 @@
+use std::collections::HashMap;
+use std::fmt;
 use wasm_bindgen::prelude::*;
 
 #[wasm_bindgen]
 extern "C" {
     fn alert(s: &str);
-    
+
     #[wasm_bindgen(js_namespace = console)]
     fn log(s: &str);
 }
@@ -15,37 +17,213 @@ macro_rules! console_log {
 #[wasm_bindgen]
 pub fn calculate(expression: &str) -> Result<f64, JsValue> {
     console_log!("Calculating: {}", expression);
-    
-    match evaluate_expression(expression) {
+
+    let no_vars = HashMap::new();
+    let ctx = EvalContext {
+        vars: &no_vars,
+        last: None,
+    };
+
+    match evaluate_expression(expression, &ctx) {
         Ok(result) => {
             console_log!("Result: {}", result);
             Ok(result)
         }
         Err(e) => {
             console_log!("Error: {}", e);
-            Err(JsValue::from_str(&e))
+            Err(e.into_js_value())
         }
     }
 }
 
-fn evaluate_expression(expr: &str) -> Result<f64, String> {
-    let expr = expr.replace(" ", "");
-    
-    if expr.is_empty() {
-        return Err("Empty expression".to_string());
+/// Read-only view over the state a parse needs to resolve identifiers:
+/// assigned variables plus the previous result (`ans`).
+struct EvalContext<'a> {
+    vars: &'a HashMap<String, f64>,
+    last: Option<f64>,
+}
+
+/// A stateful calculator session: remembers assigned variables and the
+/// result of the last evaluation so a REPL-style frontend can build up
+/// computations across calls (`x = 5`, `x * 2`, `ans + 1`).
+#[derive(Default)]
+#[wasm_bindgen]
+pub struct Calculator {
+    variables: HashMap<String, f64>,
+    last: Option<f64>,
+}
+
+#[wasm_bindgen]
+impl Calculator {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Calculator {
+        Calculator::default()
     }
-    
-    // Simple recursive descent parser for basic arithmetic
-    let mut tokens = tokenize(&expr)?;
-    let result = parse_expression(&mut tokens)?;
-    
-    if !tokens.is_empty() {
-        return Err("Unexpected tokens at end of expression".to_string());
+
+    pub fn eval(&mut self, expr: &str) -> Result<f64, JsValue> {
+        console_log!("Evaluating: {}", expr);
+
+        match self.eval_inner(expr) {
+            Ok(result) => {
+                console_log!("Result: {}", result);
+                Ok(result)
+            }
+            Err(e) => {
+                console_log!("Error: {}", e);
+                Err(e.into_js_value())
+            }
+        }
+    }
+
+    fn eval_inner(&mut self, expr: &str) -> Result<f64, CalcError> {
+        if expr.trim().is_empty() {
+            return Err(CalcError::EmptyExpression);
+        }
+
+        let mut tokens = tokenize(expr)?;
+
+        let assign_target = match tokens.as_slice() {
+            [Spanned { token: Token::Ident(name), pos }, Spanned { token: Token::Equals, .. }, ..] => {
+                Some((name.clone(), *pos))
+            }
+            _ => None,
+        };
+
+        let result = if let Some((name, name_pos)) = assign_target {
+            if is_reserved_identifier(&name) {
+                return Err(CalcError::Message {
+                    text: format!("Cannot assign to reserved name '{}'", name),
+                    pos: Some(name_pos),
+                });
+            }
+            tokens.remove(0); // identifier
+            tokens.remove(0); // '='
+            let ctx = EvalContext {
+                vars: &self.variables,
+                last: self.last,
+            };
+            let value = parse_bitor(&mut tokens, &ctx, expr.len())?;
+            expect_exhausted(&tokens)?;
+            self.variables.insert(name, value);
+            value
+        } else {
+            let ctx = EvalContext {
+                vars: &self.variables,
+                last: self.last,
+            };
+            let value = parse_bitor(&mut tokens, &ctx, expr.len())?;
+            expect_exhausted(&tokens)?;
+            value
+        };
+
+        self.last = Some(result);
+        Ok(result)
     }
-    
+}
+
+fn evaluate_expression(expr: &str, ctx: &EvalContext) -> Result<f64, CalcError> {
+    if expr.trim().is_empty() {
+        return Err(CalcError::EmptyExpression);
+    }
+
+    // Simple recursive descent parser for basic arithmetic
+    let mut tokens = tokenize(expr)?;
+    let result = parse_bitor(&mut tokens, ctx, expr.len())?;
+    expect_exhausted(&tokens)?;
+
     Ok(result)
 }
 
+fn expect_exhausted(tokens: &[Spanned]) -> Result<(), CalcError> {
+    match tokens.first() {
+        Some(spanned) => Err(CalcError::TrailingTokens { pos: spanned.pos }),
+        None => Ok(()),
+    }
+}
+
+/// A structured evaluation error carrying the character offset it occurred
+/// at (where meaningful), so a frontend can underline the offending span
+/// instead of just showing text.
+#[derive(Debug, Clone)]
+enum CalcError {
+    EmptyExpression,
+    UnexpectedChar { ch: char, pos: usize },
+    InvalidNumber { text: String, pos: usize },
+    DivisionByZero,
+    MissingCloseParen { pos: usize },
+    UnexpectedToken { pos: usize },
+    TrailingTokens { pos: usize },
+    /// Evaluation-time errors (unknown function, undefined variable, type
+    /// mismatch). `pos` is the offset of the identifier/operator at fault
+    /// when one is available.
+    Message { text: String, pos: Option<usize> },
+}
+
+impl CalcError {
+    fn kind(&self) -> &'static str {
+        match self {
+            CalcError::EmptyExpression => "EmptyExpression",
+            CalcError::UnexpectedChar { .. } => "UnexpectedChar",
+            CalcError::InvalidNumber { .. } => "InvalidNumber",
+            CalcError::DivisionByZero => "DivisionByZero",
+            CalcError::MissingCloseParen { .. } => "MissingCloseParen",
+            CalcError::UnexpectedToken { .. } => "UnexpectedToken",
+            CalcError::TrailingTokens { .. } => "TrailingTokens",
+            CalcError::Message { .. } => "EvaluationError",
+        }
+    }
+
+    fn position(&self) -> Option<usize> {
+        match self {
+            CalcError::UnexpectedChar { pos, .. }
+            | CalcError::InvalidNumber { pos, .. }
+            | CalcError::MissingCloseParen { pos }
+            | CalcError::UnexpectedToken { pos }
+            | CalcError::TrailingTokens { pos } => Some(*pos),
+            CalcError::Message { pos, .. } => *pos,
+            CalcError::EmptyExpression | CalcError::DivisionByZero => None,
+        }
+    }
+
+    /// Serializes this error into the `{ kind, message, position }` shape
+    /// the frontend expects so it can underline the offending character.
+    fn into_js_value(self) -> JsValue {
+        let obj = js_sys::Object::new();
+        let message = self.to_string();
+        let _ = js_sys::Reflect::set(&obj, &JsValue::from_str("kind"), &JsValue::from_str(self.kind()));
+        let _ = js_sys::Reflect::set(&obj, &JsValue::from_str("message"), &JsValue::from_str(&message));
+        let position = match self.position() {
+            Some(pos) => JsValue::from_f64(pos as f64),
+            None => JsValue::NULL,
+        };
+        let _ = js_sys::Reflect::set(&obj, &JsValue::from_str("position"), &position);
+        obj.into()
+    }
+}
+
+impl fmt::Display for CalcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CalcError::EmptyExpression => write!(f, "Empty expression"),
+            CalcError::UnexpectedChar { ch, pos } => {
+                write!(f, "Unexpected character '{}' at position {}", ch, pos)
+            }
+            CalcError::InvalidNumber { text, pos } => {
+                write!(f, "Invalid number '{}' at position {}", text, pos)
+            }
+            CalcError::DivisionByZero => write!(f, "Division by zero"),
+            CalcError::MissingCloseParen { pos } => {
+                write!(f, "Missing closing parenthesis at position {}", pos)
+            }
+            CalcError::UnexpectedToken { pos } => write!(f, "Unexpected token at position {}", pos),
+            CalcError::TrailingTokens { pos } => {
+                write!(f, "Unexpected tokens at position {}", pos)
+            }
+            CalcError::Message { text, .. } => write!(f, "{}", text),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 enum Token {
     Number(f64),
@@ -55,123 +233,663 @@ enum Token {
     Divide,
     LeftParen,
     RightParen,
+    Caret,
+    Ampersand,
+    Pipe,
+    Xor,
+    Tilde,
+    Shl,
+    Shr,
+    Ident(String),
+    Comma,
+    Equals,
+    Percent,
+}
+
+/// A token tagged with the character offset it started at, so parse errors
+/// can report exactly where they occurred.
+#[derive(Debug, Clone)]
+struct Spanned {
+    token: Token,
+    pos: usize,
 }
 
-fn tokenize(expr: &str) -> Result<Vec<Token>, String> {
+fn tokenize(expr: &str) -> Result<Vec<Spanned>, CalcError> {
     let mut tokens = Vec::new();
-    let mut chars = expr.chars().peekable();
-    
-    while let Some(&ch) = chars.peek() {
+    let mut chars = expr.char_indices().peekable();
+
+    while let Some(&(pos, ch)) = chars.peek() {
         match ch {
+            ' ' => {
+                chars.next();
+            }
             '0'..='9' | '.' => {
+                if ch == '0' {
+                    let mut lookahead = chars.clone();
+                    lookahead.next();
+                    if let Some(&(_, prefix)) = lookahead.peek() {
+                        let radix_kind = match prefix {
+                            'x' | 'X' => Some(16),
+                            'b' | 'B' => Some(2),
+                            'o' | 'O' => Some(8),
+                            _ => None,
+                        };
+                        if let Some(radix) = radix_kind {
+                            chars.next(); // consume '0'
+                            chars.next(); // consume prefix
+                            let mut digits = String::new();
+                            while let Some(&(_, ch)) = chars.peek() {
+                                if ch.is_digit(radix) {
+                                    digits.push(ch);
+                                    chars.next();
+                                } else {
+                                    break;
+                                }
+                            }
+                            if digits.is_empty() {
+                                return Err(CalcError::InvalidNumber {
+                                    text: format!("0{}", prefix),
+                                    pos,
+                                });
+                            }
+                            match i64::from_str_radix(&digits, radix) {
+                                Ok(n) => tokens.push(Spanned { token: Token::Number(n as f64), pos }),
+                                Err(_) => {
+                                    return Err(CalcError::InvalidNumber {
+                                        text: format!("0{}{}", prefix, digits),
+                                        pos,
+                                    })
+                                }
+                            }
+                            continue;
+                        }
+                    }
+                }
+
                 let mut number = String::new();
-                while let Some(&ch) = chars.peek() {
+                while let Some(&(_, ch)) = chars.peek() {
                     if ch.is_ascii_digit() || ch == '.' {
-                        number.push(chars.next().unwrap());
+                        number.push(ch);
+                        chars.next();
                     } else {
                         break;
                     }
                 }
                 match number.parse::<f64>() {
-                    Ok(n) => tokens.push(Token::Number(n)),
-                    Err(_) => return Err(format!("Invalid number: {}", number)),
+                    Ok(n) => tokens.push(Spanned { token: Token::Number(n), pos }),
+                    Err(_) => return Err(CalcError::InvalidNumber { text: number, pos }),
                 }
             }
             '+' => {
-                tokens.push(Token::Plus);
+                tokens.push(Spanned { token: Token::Plus, pos });
                 chars.next();
             }
             '-' => {
-                tokens.push(Token::Minus);
+                tokens.push(Spanned { token: Token::Minus, pos });
                 chars.next();
             }
             '*' => {
-                tokens.push(Token::Multiply);
+                tokens.push(Spanned { token: Token::Multiply, pos });
                 chars.next();
             }
             '/' => {
-                tokens.push(Token::Divide);
+                tokens.push(Spanned { token: Token::Divide, pos });
                 chars.next();
             }
             '(' => {
-                tokens.push(Token::LeftParen);
+                tokens.push(Spanned { token: Token::LeftParen, pos });
                 chars.next();
             }
             ')' => {
-                tokens.push(Token::RightParen);
+                tokens.push(Spanned { token: Token::RightParen, pos });
+                chars.next();
+            }
+            ',' => {
+                tokens.push(Spanned { token: Token::Comma, pos });
+                chars.next();
+            }
+            '=' => {
+                tokens.push(Spanned { token: Token::Equals, pos });
+                chars.next();
+            }
+            '%' => {
+                tokens.push(Spanned { token: Token::Percent, pos });
+                chars.next();
+            }
+            'a'..='z' | 'A'..='Z' | '_' => {
+                let mut ident = String::new();
+                while let Some(&(_, ch)) = chars.peek() {
+                    if ch.is_ascii_alphanumeric() || ch == '_' {
+                        ident.push(ch);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push(Spanned { token: Token::Ident(ident), pos });
+            }
+            '^' => {
+                chars.next();
+                // `^^` is bitwise XOR; a lone `^` stays the power operator.
+                if matches!(chars.peek(), Some(&(_, '^'))) {
+                    chars.next();
+                    tokens.push(Spanned { token: Token::Xor, pos });
+                } else {
+                    tokens.push(Spanned { token: Token::Caret, pos });
+                }
+            }
+            '&' => {
+                tokens.push(Spanned { token: Token::Ampersand, pos });
+                chars.next();
+            }
+            '|' => {
+                tokens.push(Spanned { token: Token::Pipe, pos });
                 chars.next();
             }
-            _ => return Err(format!("Unexpected character: {}", ch)),
+            '~' => {
+                tokens.push(Spanned { token: Token::Tilde, pos });
+                chars.next();
+            }
+            '<' => {
+                chars.next();
+                if matches!(chars.next(), Some((_, '<'))) {
+                    tokens.push(Spanned { token: Token::Shl, pos });
+                } else {
+                    return Err(CalcError::UnexpectedChar { ch: '<', pos });
+                }
+            }
+            '>' => {
+                chars.next();
+                if matches!(chars.next(), Some((_, '>'))) {
+                    tokens.push(Spanned { token: Token::Shr, pos });
+                } else {
+                    return Err(CalcError::UnexpectedChar { ch: '>', pos });
+                }
+            }
+            _ => return Err(CalcError::UnexpectedChar { ch, pos }),
         }
     }
-    
+
     Ok(tokens)
 }
 
-fn parse_expression(tokens: &mut Vec<Token>) -> Result<f64, String> {
-    let mut result = parse_term(tokens)?;
-    
-    while !tokens.is_empty() {
-        match &tokens[0] {
+fn to_integer(value: f64, pos: usize) -> Result<i64, CalcError> {
+    if value.fract() != 0.0 {
+        return Err(CalcError::Message {
+            text: "Bitwise operators require integer operands".to_string(),
+            pos: Some(pos),
+        });
+    }
+    Ok(value as i64)
+}
+
+/// Validates a shift amount before it reaches `<<`/`>>`, which panic on
+/// overflow in debug builds (and silently wrap in release) for amounts
+/// outside `0..64`.
+fn to_shift_amount(value: i64, pos: usize) -> Result<u32, CalcError> {
+    if !(0..64).contains(&value) {
+        return Err(CalcError::Message {
+            text: format!("Shift amount {} out of range (must be 0-63)", value),
+            pos: Some(pos),
+        });
+    }
+    Ok(value as u32)
+}
+
+/// True when `tokens` opens with something that can start an operand: a
+/// number, identifier, opening paren, or a unary prefix (`- + ~`) followed
+/// (recursively) by one of those. Skipping past unary prefixes matters for
+/// `ends_with_percent` below: in `10 % -3`, the `-3` is another operand
+/// coming up, not a lone trailing `%`.
+fn looks_like_operand_start(tokens: &[Spanned]) -> bool {
+    match tokens.first() {
+        Some(Spanned {
+            token: Token::Number(_) | Token::Ident(_) | Token::LeftParen,
+            ..
+        }) => true,
+        Some(Spanned { token: Token::Minus | Token::Plus | Token::Tilde, .. }) => {
+            looks_like_operand_start(&tokens[1..])
+        }
+        _ => false,
+    }
+}
+
+/// True when a `%` immediately ahead is the postfix "percent" meaning
+/// (`50%` == `0.5`) rather than the binary modulo operator (`7 % 3`).
+/// Distinguished by what follows the `%`: modulo is only unambiguous when
+/// another operand starts right after it, so a bare trailing `%` or one
+/// followed by an operator/closing token is read as a percentage. Because
+/// the tokenizer discards whitespace, `10% - 3` and `10 % -3` are
+/// indistinguishable here; we deliberately favor modulo whenever an operand
+/// (including a signed one) follows.
+fn ends_with_percent(tokens: &[Spanned]) -> bool {
+    match tokens.first() {
+        Some(Spanned { token: Token::Percent, .. }) => !looks_like_operand_start(&tokens[1..]),
+        _ => false,
+    }
+}
+
+fn parse_bitor(tokens: &mut Vec<Spanned>, ctx: &EvalContext, end_pos: usize) -> Result<f64, CalcError> {
+    let mut result = parse_xor(tokens, ctx, end_pos)?;
+
+    while let Some(spanned) = tokens.first() {
+        match spanned.token {
+            Token::Pipe => {
+                let op_pos = spanned.pos;
+                tokens.remove(0);
+                let rhs = parse_xor(tokens, ctx, end_pos)?;
+                result = (to_integer(result, op_pos)? | to_integer(rhs, op_pos)?) as f64;
+            }
+            _ => break,
+        }
+    }
+
+    Ok(result)
+}
+
+fn parse_xor(tokens: &mut Vec<Spanned>, ctx: &EvalContext, end_pos: usize) -> Result<f64, CalcError> {
+    let mut result = parse_band(tokens, ctx, end_pos)?;
+
+    while let Some(spanned) = tokens.first() {
+        match spanned.token {
+            Token::Xor => {
+                let op_pos = spanned.pos;
+                tokens.remove(0);
+                let rhs = parse_band(tokens, ctx, end_pos)?;
+                result = (to_integer(result, op_pos)? ^ to_integer(rhs, op_pos)?) as f64;
+            }
+            _ => break,
+        }
+    }
+
+    Ok(result)
+}
+
+fn parse_band(tokens: &mut Vec<Spanned>, ctx: &EvalContext, end_pos: usize) -> Result<f64, CalcError> {
+    let mut result = parse_shift(tokens, ctx, end_pos)?;
+
+    while let Some(spanned) = tokens.first() {
+        match spanned.token {
+            Token::Ampersand => {
+                let op_pos = spanned.pos;
+                tokens.remove(0);
+                let rhs = parse_shift(tokens, ctx, end_pos)?;
+                result = (to_integer(result, op_pos)? & to_integer(rhs, op_pos)?) as f64;
+            }
+            _ => break,
+        }
+    }
+
+    Ok(result)
+}
+
+fn parse_shift(tokens: &mut Vec<Spanned>, ctx: &EvalContext, end_pos: usize) -> Result<f64, CalcError> {
+    let mut result = parse_expression(tokens, ctx, end_pos)?;
+
+    while let Some(spanned) = tokens.first() {
+        match spanned.token {
+            Token::Shl => {
+                let op_pos = spanned.pos;
+                tokens.remove(0);
+                let rhs = parse_expression(tokens, ctx, end_pos)?;
+                let amount = to_shift_amount(to_integer(rhs, op_pos)?, op_pos)?;
+                result = (to_integer(result, op_pos)? << amount) as f64;
+            }
+            Token::Shr => {
+                let op_pos = spanned.pos;
+                tokens.remove(0);
+                let rhs = parse_expression(tokens, ctx, end_pos)?;
+                let amount = to_shift_amount(to_integer(rhs, op_pos)?, op_pos)?;
+                result = (to_integer(result, op_pos)? >> amount) as f64;
+            }
+            _ => break,
+        }
+    }
+
+    Ok(result)
+}
+
+fn parse_expression(tokens: &mut Vec<Spanned>, ctx: &EvalContext, end_pos: usize) -> Result<f64, CalcError> {
+    let mut result = parse_term(tokens, ctx, end_pos)?;
+
+    while let Some(spanned) = tokens.first() {
+        match spanned.token {
             Token::Plus => {
                 tokens.remove(0);
-                result += parse_term(tokens)?;
+                result += parse_term(tokens, ctx, end_pos)?;
             }
             Token::Minus => {
                 tokens.remove(0);
-                result -= parse_term(tokens)?;
+                result -= parse_term(tokens, ctx, end_pos)?;
             }
             _ => break,
         }
     }
-    
+
     Ok(result)
 }
 
-fn parse_term(tokens: &mut Vec<Token>) -> Result<f64, String> {
-    let mut result = parse_factor(tokens)?;
-    
-    while !tokens.is_empty() {
-        match &tokens[0] {
+fn parse_term(tokens: &mut Vec<Spanned>, ctx: &EvalContext, end_pos: usize) -> Result<f64, CalcError> {
+    let mut result = parse_unary(tokens, ctx, end_pos)?;
+
+    while let Some(spanned) = tokens.first() {
+        match spanned.token {
             Token::Multiply => {
                 tokens.remove(0);
-                result *= parse_factor(tokens)?;
+                result *= parse_unary(tokens, ctx, end_pos)?;
             }
             Token::Divide => {
                 tokens.remove(0);
-                let divisor = parse_factor(tokens)?;
+                let divisor = parse_unary(tokens, ctx, end_pos)?;
                 if divisor == 0.0 {
-                    return Err("Division by zero".to_string());
+                    return Err(CalcError::DivisionByZero);
                 }
                 result /= divisor;
             }
+            Token::Percent => {
+                let op_pos = spanned.pos;
+                tokens.remove(0);
+                let divisor = parse_unary(tokens, ctx, end_pos)?;
+                if divisor == 0.0 {
+                    return Err(CalcError::Message {
+                        text: "Modulo by zero".to_string(),
+                        pos: Some(op_pos),
+                    });
+                }
+                // Truncating remainder (same sign as the dividend), matching Rust's `%`.
+                result -= divisor * (result / divisor).trunc();
+            }
             _ => break,
         }
     }
-    
+
     Ok(result)
 }
 
-fn parse_factor(tokens: &mut Vec<Token>) -> Result<f64, String> {
-    if tokens.is_empty() {
-        return Err("Unexpected end of expression".to_string());
+/// Unary prefix operators (`- + ~`) bind looser than `^`, matching the
+/// convention most calculators and languages use: `-2^2` is `-(2^2) = -4`,
+/// not `(-2)^2`. So this sits above `parse_power`, not inside the atom it
+/// parses, and `parse_power`'s exponent recurses back into this (rather
+/// than straight into itself) so a signed exponent like `2^-2` still works.
+fn parse_unary(tokens: &mut Vec<Spanned>, ctx: &EvalContext, end_pos: usize) -> Result<f64, CalcError> {
+    match tokens.first() {
+        Some(Spanned { token: Token::Minus, .. }) => {
+            tokens.remove(0);
+            let operand = parse_unary(tokens, ctx, end_pos)?;
+            Ok(-operand)
+        }
+        Some(Spanned { token: Token::Plus, .. }) => {
+            tokens.remove(0);
+            parse_unary(tokens, ctx, end_pos)
+        }
+        Some(Spanned { token: Token::Tilde, pos }) => {
+            let op_pos = *pos;
+            tokens.remove(0);
+            let operand = parse_unary(tokens, ctx, end_pos)?;
+            Ok(!to_integer(operand, op_pos)? as f64)
+        }
+        _ => parse_power(tokens, ctx, end_pos),
     }
-    
-    match tokens.remove(0) {
-        Token::Number(n) => Ok(n),
-        Token::Minus => {
-            let factor = parse_factor(tokens)?;
-            Ok(-factor)
+}
+
+fn parse_power(tokens: &mut Vec<Spanned>, ctx: &EvalContext, end_pos: usize) -> Result<f64, CalcError> {
+    let base = parse_atom(tokens, ctx, end_pos)?;
+
+    if matches!(tokens.first(), Some(Spanned { token: Token::Caret, .. })) {
+        tokens.remove(0);
+        // Right-associative, so 2^3^2 == 2^(3^2): recursing through
+        // parse_unary (rather than looping) also lets the exponent carry
+        // its own sign, e.g. 2^-2 == 0.25.
+        let exponent = parse_unary(tokens, ctx, end_pos)?;
+        Ok(base.powf(exponent))
+    } else {
+        Ok(base)
+    }
+}
+
+fn parse_atom(tokens: &mut Vec<Spanned>, ctx: &EvalContext, end_pos: usize) -> Result<f64, CalcError> {
+    let spanned = match tokens.first() {
+        Some(_) => tokens.remove(0),
+        None => return Err(CalcError::UnexpectedToken { pos: end_pos }),
+    };
+    let pos = spanned.pos;
+
+    match spanned.token {
+        Token::Number(n) => {
+            if ends_with_percent(tokens) {
+                tokens.remove(0); // consume '%'
+                Ok(n / 100.0)
+            } else {
+                Ok(n)
+            }
         }
-        Token::Plus => parse_factor(tokens),
         Token::LeftParen => {
-            let result = parse_expression(tokens)?;
-            if tokens.is_empty() || !matches!(tokens[0], Token::RightParen) {
-                return Err("Missing closing parenthesis".to_string());
+            let inner = parse_bitor(tokens, ctx, end_pos)?;
+            match tokens.first() {
+                Some(Spanned { token: Token::RightParen, .. }) => {
+                    tokens.remove(0);
+                    if ends_with_percent(tokens) {
+                        tokens.remove(0); // consume '%'
+                        Ok(inner / 100.0)
+                    } else {
+                        Ok(inner)
+                    }
+                }
+                Some(spanned) => Err(CalcError::MissingCloseParen { pos: spanned.pos }),
+                None => Err(CalcError::MissingCloseParen { pos: end_pos }),
             }
-            tokens.remove(0); // Remove the closing parenthesis
-            Ok(result)
         }
-        _ => Err("Unexpected token".to_string()),
+        Token::Ident(name) => {
+            if matches!(tokens.first(), Some(Spanned { token: Token::LeftParen, .. })) {
+                tokens.remove(0); // Remove the opening parenthesis
+                let mut args = Vec::new();
+                if !matches!(tokens.first(), Some(Spanned { token: Token::RightParen, .. })) {
+                    loop {
+                        args.push(parse_bitor(tokens, ctx, end_pos)?);
+                        if matches!(tokens.first(), Some(Spanned { token: Token::Comma, .. })) {
+                            tokens.remove(0);
+                            continue;
+                        }
+                        break;
+                    }
+                }
+                match tokens.first() {
+                    Some(Spanned { token: Token::RightParen, .. }) => {
+                        tokens.remove(0);
+                    }
+                    Some(spanned) => return Err(CalcError::MissingCloseParen { pos: spanned.pos }),
+                    None => return Err(CalcError::MissingCloseParen { pos: end_pos }),
+                }
+                call_function(&name, &args, pos)
+            } else {
+                resolve_identifier(&name, ctx, pos)
+            }
+        }
+        _ => Err(CalcError::UnexpectedToken { pos }),
+    }
+}
+
+/// Names `resolve_identifier` special-cases ahead of variable lookup —
+/// assigning to one of these would silently never be visible again.
+fn is_reserved_identifier(name: &str) -> bool {
+    matches!(name, "pi" | "e" | "ans")
+}
+
+fn resolve_identifier(name: &str, ctx: &EvalContext, pos: usize) -> Result<f64, CalcError> {
+    match name {
+        "pi" => Ok(std::f64::consts::PI),
+        "e" => Ok(std::f64::consts::E),
+        "ans" => ctx.last.ok_or_else(|| CalcError::Message {
+            text: "No previous result".to_string(),
+            pos: Some(pos),
+        }),
+        _ => ctx.vars.get(name).copied().ok_or_else(|| CalcError::Message {
+            text: format!("Undefined variable: {}", name),
+            pos: Some(pos),
+        }),
+    }
+}
+
+fn call_function(name: &str, args: &[f64], pos: usize) -> Result<f64, CalcError> {
+    fn arity_error(name: &str, expected: usize, got: usize, pos: usize) -> CalcError {
+        CalcError::Message {
+            text: format!("{} expects {} argument(s), got {}", name, expected, got),
+            pos: Some(pos),
+        }
+    }
+
+    match name {
+        "sqrt" if args.len() == 1 => Ok(args[0].sqrt()),
+        "abs" if args.len() == 1 => Ok(args[0].abs()),
+        "ln" if args.len() == 1 => Ok(args[0].ln()),
+        "log" if args.len() == 1 => Ok(args[0].log10()),
+        "sin" if args.len() == 1 => Ok(args[0].sin()),
+        "cos" if args.len() == 1 => Ok(args[0].cos()),
+        "tan" if args.len() == 1 => Ok(args[0].tan()),
+        "floor" if args.len() == 1 => Ok(args[0].floor()),
+        "ceil" if args.len() == 1 => Ok(args[0].ceil()),
+        "round" if args.len() == 1 => Ok(args[0].round()),
+        "pow" if args.len() == 2 => Ok(args[0].powf(args[1])),
+        "min" if args.len() == 2 => Ok(args[0].min(args[1])),
+        "max" if args.len() == 2 => Ok(args[0].max(args[1])),
+        "sqrt" | "abs" | "ln" | "log" | "sin" | "cos" | "tan" | "floor" | "ceil" | "round" => {
+            Err(arity_error(name, 1, args.len(), pos))
+        }
+        "pow" | "min" | "max" => Err(arity_error(name, 2, args.len(), pos)),
+        _ => Err(CalcError::Message {
+            text: format!("Unknown function: {}", name),
+            pos: Some(pos),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calc(expr: &str) -> Result<f64, CalcError> {
+        let no_vars = HashMap::new();
+        let ctx = EvalContext {
+            vars: &no_vars,
+            last: None,
+        };
+        evaluate_expression(expr, &ctx)
+    }
+
+    #[test]
+    fn unary_minus_binds_looser_than_power() {
+        assert_eq!(calc("-2^2").unwrap(), -4.0);
+        assert_eq!(calc("2^-2").unwrap(), 0.25);
+        assert_eq!(calc("2^3^2").unwrap(), 512.0);
+    }
+
+    #[test]
+    fn bitwise_operators_happy_path() {
+        assert_eq!(calc("5&3").unwrap(), 1.0);
+        assert_eq!(calc("5|2").unwrap(), 7.0);
+        assert_eq!(calc("5^^3").unwrap(), 6.0);
+        assert_eq!(calc("~5").unwrap(), -6.0);
+        assert_eq!(calc("1<<3").unwrap(), 8.0);
+        assert_eq!(calc("16>>2").unwrap(), 4.0);
+    }
+
+    #[test]
+    fn shift_amount_out_of_range_is_an_error() {
+        assert!(calc("5<<64").is_err());
+        assert!(calc("5<<-1").is_err());
+        assert!(calc("5>>64").is_err());
+        assert_eq!(calc("5<<1").unwrap(), 10.0);
+        assert_eq!(calc("5<<63").unwrap(), (5i64 << 63) as f64);
+    }
+
+    #[test]
+    fn modulo_with_negative_rhs_is_not_mistaken_for_percent() {
+        assert_eq!(calc("10%-3").unwrap(), 1.0);
+        assert_eq!(calc("10 % -3").unwrap(), 1.0);
+    }
+
+    #[test]
+    fn postfix_percent_after_parenthesized_subexpression() {
+        assert_eq!(calc("(50)%").unwrap(), 0.5);
+        assert_eq!(calc("(40+10)%").unwrap(), 0.5);
+    }
+
+    #[test]
+    fn radix_overflow_error_text_holds_the_literal() {
+        let err = calc("0xFFFFFFFFFFFFFFFFF").unwrap_err();
+        match err {
+            CalcError::InvalidNumber { text, .. } => assert_eq!(text, "0xFFFFFFFFFFFFFFFFF"),
+            other => panic!("expected InvalidNumber, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn error_position_is_against_the_original_input() {
+        let no_vars = HashMap::new();
+        let ctx = EvalContext { vars: &no_vars, last: None };
+        let err = evaluate_expression("1 + @", &ctx).unwrap_err();
+        match err {
+            CalcError::UnexpectedChar { ch, pos } => {
+                assert_eq!(ch, '@');
+                assert_eq!(pos, 4);
+            }
+            other => panic!("expected UnexpectedChar, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn evaluation_errors_carry_a_position() {
+        let err = calc("foo+1").unwrap_err();
+        assert_eq!(err.position(), Some(0));
+
+        let err = calc("sqrt(1,2)").unwrap_err();
+        assert_eq!(err.position(), Some(0));
+
+        let err = calc("5<<64").unwrap_err();
+        assert_eq!(err.position(), Some(1));
+    }
+
+    #[test]
+    fn assigning_to_reserved_name_is_rejected() {
+        let mut calculator = Calculator::new();
+        assert!(calculator.eval_inner("pi = 5").is_err());
+        assert_eq!(calculator.eval_inner("pi").unwrap(), std::f64::consts::PI);
+    }
+
+    #[test]
+    fn variables_and_ans_carry_state_across_calls() {
+        let mut calculator = Calculator::new();
+        assert_eq!(calculator.eval_inner("x = 5").unwrap(), 5.0);
+        assert_eq!(calculator.eval_inner("x * 2").unwrap(), 10.0);
+        assert_eq!(calculator.eval_inner("ans + 1").unwrap(), 11.0);
+        assert_eq!(calculator.eval_inner("x").unwrap(), 5.0);
+    }
+
+    #[test]
+    fn hex_binary_and_octal_literals() {
+        assert_eq!(calc("0xFF").unwrap(), 255.0);
+        assert_eq!(calc("0b1010").unwrap(), 10.0);
+        assert_eq!(calc("0o17").unwrap(), 15.0);
+        assert!(calc("0x").is_err());
+        assert!(calc("0b").is_err());
+    }
+
+    #[test]
+    fn named_constants() {
+        assert_eq!(calc("pi").unwrap(), std::f64::consts::PI);
+        assert_eq!(calc("e").unwrap(), std::f64::consts::E);
+    }
+
+    #[test]
+    fn function_library() {
+        assert_eq!(calc("sqrt(16)").unwrap(), 4.0);
+        assert_eq!(calc("abs(-5)").unwrap(), 5.0);
+        assert_eq!(calc("max(3,7)").unwrap(), 7.0);
+        assert_eq!(calc("min(3,7)").unwrap(), 3.0);
+        assert_eq!(calc("pow(2,10)").unwrap(), 1024.0);
+        assert_eq!(calc("floor(1.9)").unwrap(), 1.0);
+        assert_eq!(calc("ceil(1.1)").unwrap(), 2.0);
+        assert!(calc("sqrt(1,2)").is_err());
+        assert!(calc("bogus(1)").is_err());
     }
 }